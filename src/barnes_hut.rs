@@ -0,0 +1,187 @@
+use quicksilver::geom::Vector;
+
+// Barnes-Hut approximation: group distant bodies into a single pseudo-body
+// (their combined mass at their center of mass) instead of visiting each of
+// them individually. Cuts force evaluation from O(n^2) to roughly O(n log n).
+
+// Bodies at (or extremely near) the same position would otherwise keep
+// landing in the same quadrant forever, splitting the same leaf at every
+// level and recursing until the stack overflows. Once insertion reaches this
+// depth, stop subdividing and fold any further coincident bodies into one
+// Cluster instead.
+const MAX_DEPTH: usize = 64;
+
+enum Node {
+    Empty,
+    // A single body. Keeps its index so the acceleration walk can skip
+    // a body's own leaf instead of dividing by a zero distance.
+    Leaf { index: usize, position: Vector, mass: f32 },
+    // Two or more bodies too close together to separate by further
+    // subdivision. Treated as one pseudo-body; a body belonging to the
+    // cluster excludes the whole cluster rather than just itself.
+    Cluster { indices: Vec<usize>, position: Vector, mass: f32 },
+    Internal {
+        mass: f32,
+        center_of_mass: Vector,
+        center: Vector,
+        half_size: f32,
+        children: Box<[Node; 4]>,
+    },
+}
+
+impl Node {
+    fn quadrant_of(center: Vector, position: Vector) -> usize {
+        let right = position.x >= center.x;
+        let top = position.y >= center.y;
+        match (right, top) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+        }
+    }
+
+    fn child_center(center: Vector, half_size: f32, quadrant: usize) -> Vector {
+        let offset = half_size / 2.0;
+        match quadrant {
+            0 => center + Vector::new(-offset, -offset),
+            1 => center + Vector::new(offset, -offset),
+            2 => center + Vector::new(-offset, offset),
+            _ => center + Vector::new(offset, offset),
+        }
+    }
+
+    fn insert(&mut self, index: usize, position: Vector, mass: f32, center: Vector, half_size: f32, depth: usize) {
+        match self {
+            Node::Empty => {
+                *self = Node::Leaf { index, position, mass };
+            }
+            Node::Leaf { index: old_index, position: old_position, mass: old_mass } => {
+                let (old_index, old_position, old_mass) = (*old_index, *old_position, *old_mass);
+                if (old_position.x == position.x && old_position.y == position.y) || depth >= MAX_DEPTH {
+                    *self = Node::Cluster {
+                        indices: vec![old_index, index],
+                        position: old_position,
+                        mass: old_mass + mass,
+                    };
+                    return;
+                }
+                let mut children = Box::new([Node::Empty, Node::Empty, Node::Empty, Node::Empty]);
+                let old_quadrant = Node::quadrant_of(center, old_position);
+                children[old_quadrant].insert(
+                    old_index, old_position, old_mass,
+                    Node::child_center(center, half_size, old_quadrant), half_size / 2.0, depth + 1,
+                );
+                let new_quadrant = Node::quadrant_of(center, position);
+                children[new_quadrant].insert(
+                    index, position, mass,
+                    Node::child_center(center, half_size, new_quadrant), half_size / 2.0, depth + 1,
+                );
+                *self = Node::Internal {
+                    mass: old_mass + mass,
+                    center_of_mass: (old_position * old_mass + position * mass) * (1.0 / (old_mass + mass)),
+                    center,
+                    half_size,
+                    children,
+                };
+            }
+            Node::Cluster { indices, mass: total_mass, .. } => {
+                indices.push(index);
+                *total_mass += mass;
+            }
+            Node::Internal { mass: total_mass, center_of_mass, children, .. } => {
+                *center_of_mass = (*center_of_mass * *total_mass + position * mass) * (1.0 / (*total_mass + mass));
+                *total_mass += mass;
+                let quadrant = Node::quadrant_of(center, position);
+                let child_center = Node::child_center(center, half_size, quadrant);
+                children[quadrant].insert(index, position, mass, child_center, half_size / 2.0, depth + 1);
+            }
+        }
+    }
+
+    fn acceleration_at(&self, index: usize, position: Vector, theta: f32, eps2: f32) -> Vector {
+        match self {
+            Node::Empty => Vector::new(0, 0),
+            Node::Leaf { index: other_index, position: other_position, mass } => {
+                if *other_index == index {
+                    return Vector::new(0, 0);
+                }
+                let distance = *other_position - position;
+                distance * (*mass / (distance.len2() + eps2).powf(1.5))
+            }
+            Node::Cluster { indices, position: other_position, mass } => {
+                if indices.contains(&index) {
+                    return Vector::new(0, 0);
+                }
+                let distance = *other_position - position;
+                distance * (*mass / (distance.len2() + eps2).powf(1.5))
+            }
+            Node::Internal { mass, center_of_mass, half_size, children, .. } => {
+                let distance = *center_of_mass - position;
+                if half_size * 2.0 / distance.len() < theta {
+                    distance * (*mass / (distance.len2() + eps2).powf(1.5))
+                } else {
+                    children.iter()
+                        .map(|child| child.acceleration_at(index, position, theta, eps2))
+                        .fold(Vector::new(0, 0), |sum, a| sum + a)
+                }
+            }
+        }
+    }
+}
+
+/// A quadtree over a set of bodies' positions and masses, used to approximate
+/// the net gravitational acceleration on a body without summing every pair.
+pub struct QuadTree {
+    root: Node,
+}
+
+impl QuadTree {
+    pub fn build(bodies: &[(Vector, f32)]) -> QuadTree {
+        // Seeded from the first body (not the origin) so a system placed far
+        // from (0, 0) gets a tightly-fitting root cell instead of one
+        // needlessly stretched out to cover the origin too.
+        let mut min = bodies.first().map_or(Vector::new(0, 0), |(position, _)| *position);
+        let mut max = min;
+        for (position, _) in bodies {
+            min = Vector::new(min.x.min(position.x), min.y.min(position.y));
+            max = Vector::new(max.x.max(position.x), max.y.max(position.y));
+        }
+        let center = (min + max) / 2.0;
+        // Pad a little so bodies exactly on the boundary still fall inside.
+        let half_size = ((max - min).x.max((max - min).y) / 2.0 + 1.0).max(1.0);
+
+        let mut root = Node::Empty;
+        for (index, (position, mass)) in bodies.iter().enumerate() {
+            root.insert(index, *position, *mass, center, half_size, 0);
+        }
+        QuadTree { root }
+    }
+
+    /// Approximate acceleration at the given body (identified by `index` so
+    /// the body doesn't attract itself), opening internal nodes whose
+    /// angular size `width / distance` is at least `theta`. `eps2` is the
+    /// squared Plummer softening length, added to every squared distance to
+    /// keep close encounters finite; pass 0.0 to disable softening.
+    pub fn acceleration_at(&self, index: usize, position: Vector, theta: f32, eps2: f32) -> Vector {
+        self.root.acceleration_at(index, position, theta, eps2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bodies sharing a position used to recurse into the same quadrant
+    // forever (see MAX_DEPTH); building must return instead of overflowing
+    // the stack, and a body in the resulting cluster gets no pull from it.
+    #[test]
+    fn build_with_duplicate_positions_terminates() {
+        let same_spot = Vector::new(1.0, 1.0);
+        let bodies: Vec<(Vector, f32)> = (0..8).map(|_| (same_spot, 1.0)).collect();
+        let tree = QuadTree::build(&bodies);
+        let acceleration = tree.acceleration_at(0, same_spot, 0.5, 1.0);
+        assert_eq!(acceleration.x, 0.0);
+        assert_eq!(acceleration.y, 0.0);
+    }
+}
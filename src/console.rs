@@ -0,0 +1,117 @@
+use serde_derive::*;
+
+use crate::{DEFAULT_SOFTENING_EPS, DEFAULT_THETA, DEFAULT_TIME_STEP, DEFAULT_UPDATE_RATE};
+
+/// Tunable values exposed to the in-app console, and persisted alongside the
+/// planets so a tuned configuration survives a restart.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CVars {
+    pub gravitational_constant: f32,
+    pub time_step: f32,
+    pub softening_eps: f32,
+    pub theta: f32,
+    pub update_rate: f64,
+}
+
+impl CVars {
+    pub fn defaults() -> CVars {
+        CVars {
+            gravitational_constant: 1.0,
+            time_step: DEFAULT_TIME_STEP,
+            softening_eps: DEFAULT_SOFTENING_EPS,
+            theta: DEFAULT_THETA,
+            update_rate: DEFAULT_UPDATE_RATE,
+        }
+    }
+
+    pub fn names() -> &'static [&'static str] {
+        &["gravitational_constant", "time_step", "softening_eps", "theta", "update_rate"]
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "gravitational_constant" => Some(self.gravitational_constant.to_string()),
+            "time_step" => Some(self.time_step.to_string()),
+            "softening_eps" => Some(self.softening_eps.to_string()),
+            "theta" => Some(self.theta.to_string()),
+            "update_rate" => Some(self.update_rate.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "gravitational_constant" => self.gravitational_constant = parse(name, value)?,
+            "time_step" => self.time_step = parse(name, value)?,
+            "softening_eps" => self.softening_eps = parse(name, value)?,
+            "theta" => self.theta = parse(name, value)?,
+            "update_rate" => self.update_rate = parse(name, value)?,
+            _ => return Err(format!("unknown cvar '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("'{}' is not a valid value for {}", value, name))
+}
+
+/// A drop-down text console: a single input line plus a scrollback log,
+/// rendered as an overlay while `open`.
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    pub log: Vec<String>,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console { open: false, input: String::new(), log: Vec::new() }
+    }
+
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+
+    pub fn push_char(&mut self, character: char) {
+        // Event::Typed fires alongside Event::Key for non-alphanumeric keys
+        // too, so the backquote that opens the console, and the carriage
+        // return / backspace already handled in handle_console_event, would
+        // otherwise land in the input as well.
+        if !character.is_control() && character != '`' {
+            self.input.push(character);
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        self.input.pop();
+    }
+
+    /// Takes the current input line as a command to run, logging it, and
+    /// clearing the input for the next one. Returns None for an empty line.
+    pub fn submit(&mut self) -> Option<String> {
+        if self.input.is_empty() {
+            return None;
+        }
+        let command = std::mem::replace(&mut self.input, String::new());
+        self.log.push(format!("> {}", command));
+        Some(command)
+    }
+
+    pub fn log(&mut self, line: String) {
+        self.log.push(line);
+    }
+
+    const VISIBLE_LOG_LINES: usize = 10;
+
+    pub fn render_text(&self) -> String {
+        let start = self.log.len().saturating_sub(Console::VISIBLE_LOG_LINES);
+        let mut text = self.log[start..].join("\n");
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str("> ");
+        text.push_str(&self.input);
+        text
+    }
+}
@@ -1,6 +1,7 @@
 use quicksilver::geom::Vector;
 use quicksilver::graphics::Color;
 use crate::Planet;
+use crate::trail::Trail;
 
 pub fn get_planets() -> Vec<Planet> {
     let mut planets: Vec<Planet> = Vec::new();
@@ -10,6 +11,7 @@ pub fn get_planets() -> Vec<Planet> {
         velocity: Vector::new(0, 0),
         mass: 200.0,
         color: Color::RED,
+        trail: Trail::new(Vector::new(0, 0)),
     };
     planets.push(planet);
 
@@ -18,6 +20,7 @@ pub fn get_planets() -> Vec<Planet> {
         velocity: Vector::new(0, 1.3),
         mass: 5.0,
         color: Color::GREEN,
+        trail: Trail::new(Vector::new(100, 0)),
     };
     planets.push(planet);
 
@@ -26,6 +29,7 @@ pub fn get_planets() -> Vec<Planet> {
         velocity: Vector::new(0, 1.1),
         mass: 2.0,
         color: Color::BLUE,
+        trail: Trail::new(Vector::new(200, 0)),
     };
     planets.push(planet);
 
@@ -34,6 +38,7 @@ pub fn get_planets() -> Vec<Planet> {
         velocity: Vector::new(0, 0.9),
         mass: 2.0,
         color: Color::CYAN,
+        trail: Trail::new(Vector::new(300, 0)),
     };
     planets.push(planet);
 
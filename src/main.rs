@@ -7,8 +7,17 @@ use serde_derive::*;
 use serde_json::from_slice;
 use quicksilver::saving::{save, load};
 use std::iter::once;
+use std::time::{Duration, Instant};
 
 mod default_space;
+mod barnes_hut;
+mod scripting;
+mod trail;
+mod console;
+
+use barnes_hut::QuadTree;
+use trail::Trail;
+use console::{Console, CVars};
 
 const WIDTH: f32 = 1200.0;
 const HEIGHT: f32 = 900.0;
@@ -17,6 +26,17 @@ const SAVE_PROFILE: &str = "profile1";
 // misnomers: it's delay between updates
 const DEFAULT_TIME_STEP: f32 = 0.001;
 const DEFAULT_UPDATE_RATE: f64 = 0.01;
+// Opening angle for Barnes-Hut: smaller is more accurate but slower.
+const DEFAULT_THETA: f32 = 0.5;
+// Below this many bodies, brute force is already fast and exact, so skip the tree.
+const BARNES_HUT_THRESHOLD: usize = 64;
+// Plummer softening length: keeps close encounters from blowing up to infinite acceleration.
+const DEFAULT_SOFTENING_EPS: f32 = 1.0;
+// Mouse-placed planets: starting mass, and how much holding the button adds per second.
+const MOUSE_SPAWN_BASE_MASS: f32 = 1.0;
+const MOUSE_SPAWN_MASS_PER_SECOND: f32 = 80.0;
+// Two clicks this close together count as a double-click (delete instead of place).
+const DOUBLE_CLICK_WINDOW: Duration = Duration::from_millis(350);
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Planet {
@@ -25,6 +45,7 @@ pub struct Planet {
     // per tick
     mass: f32,
     color: Color,
+    trail: Trail,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -33,10 +54,17 @@ enum Object {
     Planet(usize),
 }
 
+// What gets written by <S> and read by <L>/startup: the bodies plus the
+// tuned CVars, so a session picks up where it left off.
+#[derive(Serialize, Deserialize)]
+struct SaveData {
+    planets: Vec<Planet>,
+    cvars: CVars,
+}
+
 struct Space {
     planets: Vec<Planet>,
     paused: bool,
-    time_step: f32,
     font: Asset<Font>,
     status_text_img: Option<Image>,
     // Text rendering is kind of slow, we cache it here
@@ -44,7 +72,27 @@ struct Space {
     // Index of planet to center view at, or None for centering on barycenter
     rotate_with: Option<Object>,
     // The view will rotate so this planet will be at the right
-    clear_screen: bool,
+    // Force the brute-force O(n^2) reference path even above BARNES_HUT_THRESHOLD.
+    force_brute: bool,
+    integrator: Integrator,
+    // 0.0 (with use_softening off) reproduces the bare 1/r^2 law.
+    use_softening: bool,
+    merge_collisions: bool,
+    // World-space transform used by the last draw(), needed to convert mouse
+    // screen coordinates back into world coordinates for placing planets.
+    view_transform: Transform,
+    // World position and press time of an in-progress click-and-drag placement.
+    pending_placement: Option<(Vector, Instant)>,
+    // A placement that's been released but not yet committed, because it
+    // might still turn out to be the first half of a double-click. Holds the
+    // planet to spawn and the original press time, so it commits exactly
+    // when that press stops being eligible for DOUBLE_CLICK_WINDOW.
+    pending_spawn: Option<(Planet, Instant)>,
+    // Time of the last completed click, for double-click detection.
+    last_click_at: Option<Instant>,
+    // Gravitational constant, time step, softening length, Barnes-Hut theta, update rate.
+    cvars: CVars,
+    console: Console,
 }
 
 impl Space {
@@ -58,6 +106,114 @@ impl Space {
         self.status_text_img = None;
     }
 
+    fn run_script(&mut self, filename: &str) -> () {
+        self.planets = match scripting::run(filename) {
+            Ok(planets) => planets,
+            Err(error) => {
+                eprintln!("{}", error);
+                default_space::get_planets()
+            }
+        };
+        self.centered_at = Object::Barycenter;
+        self.rotate_with = None;
+        self.status_text_img = None;
+    }
+
+    // Undo draw()'s view transform to turn a mouse position (in window pixels)
+    // into a world-space position.
+    fn screen_to_world(&self, screen_position: Vector) -> Vector {
+        let size = Vector::new(WIDTH, HEIGHT);
+        self.view_transform.inverse() * (screen_position - size / 2.0)
+    }
+
+    // Planet nearest to `position`, if any lies within its own drawn radius of it.
+    fn planet_at(&self, position: Vector) -> Option<usize> {
+        self.planets.iter().enumerate()
+            .filter(|(_, planet)| (planet.position - position).len() < planet_radius(planet.mass))
+            .min_by(|(_, a), (_, b)| {
+                (a.position - position).len2().partial_cmp(&(b.position - position).len2()).unwrap()
+            })
+            .map(|(index, _)| index)
+    }
+
+    fn handle_console_event(&mut self, event: &Event, window: &mut Window) -> Result<()> {
+        match event {
+            Event::Key(Key::Grave, ButtonState::Pressed) | Event::Key(Key::Escape, ButtonState::Pressed) => {
+                self.console.toggle();
+            }
+            Event::Key(Key::Return, ButtonState::Pressed) => {
+                if let Some(command) = self.console.submit() {
+                    let result = self.execute_console_command(&command, window);
+                    self.console.log(result);
+                    self.status_text_img = None;
+                }
+            }
+            Event::Key(Key::Back, ButtonState::Pressed) => {
+                self.console.backspace();
+            }
+            Event::Typed(character) => {
+                self.console.push_char(*character);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Parses and runs a console command, returning the line to show in the log.
+    fn execute_console_command(&mut self, command: &str, window: &mut Window) -> String {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        match parts.as_slice() {
+            ["set", name, value] => match self.cvars.set(name, value) {
+                Ok(()) => {
+                    if *name == "update_rate" {
+                        window.set_update_rate(self.cvars.update_rate);
+                    }
+                    format!("{} = {}", name, value)
+                }
+                Err(error) => error,
+            },
+            ["get", name] => self.cvars.get(name).unwrap_or_else(|| format!("unknown cvar '{}'", name)),
+            ["list"] => if self.planets.is_empty() {
+                "no planets".to_string()
+            } else {
+                self.planets.iter().enumerate()
+                    .map(|(i, p)| format!("#{}: mass={:.2} vel=({:.2}, {:.2})", i, p.mass, p.velocity.x, p.velocity.y))
+                    .collect::<Vec<_>>().join("\n")
+            },
+            ["spawn", mass] => match mass.parse::<f32>() {
+                Ok(mass) => {
+                    self.planets.push(Planet {
+                        position: Vector::new(0, 0),
+                        velocity: Vector::new(0, 0),
+                        mass,
+                        color: Color::WHITE,
+                        trail: Trail::new(Vector::new(0, 0)),
+                    });
+                    format!("spawned planet #{}", self.planets.len() - 1)
+                }
+                Err(_) => format!("'{}' is not a valid mass", mass),
+            },
+            ["delete", index] => match index.parse::<usize>() {
+                Ok(index) if index < self.planets.len() => {
+                    self.planets.remove(index);
+                    format!("deleted planet #{}", index)
+                }
+                _ => format!("no planet #{}", index),
+            },
+            ["reset", "default"] => {
+                self.planets = default_space::get_planets();
+                "reset to default".to_string()
+            }
+            ["reset", name] if *name == "system1" || *name == "system2" || *name == "system3" => {
+                self.load_planets(&format!("{}.json", name));
+                format!("reset to {}", name)
+            }
+            ["reset", name] => format!("unknown system '{}'", name),
+            ["cvars"] => CVars::names().join(", "),
+            _ => format!("unknown command '{}'", command),
+        }
+    }
+
     fn maybe_refresh_status_text(&mut self, window: &Window) -> () {
         if self.status_text_img.is_none() {
             let paused = self.paused;
@@ -80,7 +236,11 @@ impl Space {
                 </ *> change time step\n\
                 <S> save, <L> load\n\
                 <C> center on planet, <R> rotate with planet\n\
-                <Tab> toggle screen clearing (planets leave trails, messes up text rendering)\n\
+                <B> toggle brute-force gravity, <9 0> decrease/increase Barnes-Hut theta\n\
+                <I> cycle integrator (Euler, velocity Verlet, RK4)\n\
+                <P> toggle Plummer softening, <M> toggle collision merging\n\
+                <Click+drag> place a planet and set its launch velocity, hold to grow its mass\n\
+                <Double-click> delete the planet under the cursor\n\
                 \n\
                 Sample systems:\n\
                 ---------------\n\
@@ -88,17 +248,29 @@ impl Space {
                 <F2> stable, with moon\n\
                 <F3> stable in L5 point\n\
                 <F4> Binary star\n\
+                <F5> ring.rhai, <F6> binary.rhai (Rhai scripts, procedurally generated)\n\
+                <`> toggle console (set/get cvars, spawn/delete/list/reset planets)\n\
                 \n\
                 Centered at: {}\n\
                 Rotation: {}\n\
                 Paused: {}\n\
                 Simulation time step: {}\n\
-                Update rate: {} updates/sec",
+                Update rate: {} updates/sec\n\
+                Gravity: {}, G: {}, theta: {}\n\
+                Integrator: {}\n\
+                Softening: {} (eps {}), collision merging: {}",
                 centering,
                 rotation,
                 paused,
-                self.time_step,
-                1000.0 / window.update_rate()
+                self.cvars.time_step,
+                1000.0 / window.update_rate(),
+                if self.force_brute || self.planets.len() < BARNES_HUT_THRESHOLD { "brute-force" } else { "Barnes-Hut" },
+                self.cvars.gravitational_constant,
+                self.cvars.theta,
+                self.integrator.name(),
+                self.use_softening,
+                self.cvars.softening_eps,
+                self.merge_collisions
             );
             let mut img: Option<Image> = None;
             self.font.execute(|font| {
@@ -117,9 +289,9 @@ impl Space {
 
 impl State for Space {
     fn new() -> Result<Space> {
-        let planets = match load::<Vec<Planet>>(APP_NAME, SAVE_PROFILE) {
-            Ok(planets) => planets,
-            _ => default_space::get_planets(),
+        let (planets, cvars) = match load::<SaveData>(APP_NAME, SAVE_PROFILE) {
+            Ok(data) => (data.planets, data.cvars),
+            _ => (default_space::get_planets(), CVars::defaults()),
         };
 
         let font = Asset::new(Font::load("FiraCode-Medium.ttf"));
@@ -127,27 +299,118 @@ impl State for Space {
         Ok(Space {
             planets,
             paused: true,
-            time_step: DEFAULT_TIME_STEP,
             font,
             status_text_img: Option::None,
             centered_at: Object::Barycenter,
             rotate_with: Option::None,
-            clear_screen: true,
+            force_brute: false,
+            integrator: Integrator::Euler,
+            use_softening: false,
+            merge_collisions: false,
+            view_transform: Transform::IDENTITY,
+            pending_placement: None,
+            pending_spawn: None,
+            last_click_at: None,
+            cvars,
+            console: Console::new(),
         })
     }
 
     fn update(&mut self, _window: &mut Window) -> Result<()> {
+        // Commit a released placement once its press is no longer eligible
+        // to be the first half of a double-click.
+        if let Some((planet, pressed_at)) = self.pending_spawn {
+            if pressed_at.elapsed() >= DOUBLE_CLICK_WINDOW {
+                self.planets.push(planet);
+                self.pending_spawn = None;
+                self.status_text_img = None;
+            }
+        }
+
         if !self.paused {
-            self.planets = integrate(self.time_step, &self.planets);
+            let eps2 = if self.use_softening { self.cvars.softening_eps * self.cvars.softening_eps } else { 0.0 };
+            self.planets = integrate(
+                self.cvars.time_step, &self.planets, self.cvars.theta, self.force_brute,
+                eps2, self.cvars.gravitational_constant, self.integrator,
+            );
+            if self.merge_collisions {
+                self.planets = merge_collisions(&self.planets);
+            }
         }
         Ok(())
     }
 
     fn event(&mut self, event: &Event, window: &mut Window) -> Result<()> {
+        if self.console.open {
+            return self.handle_console_event(event, window);
+        }
+
         match event {
-            Event::Key(Key::Tab, ButtonState::Pressed) => {
+            Event::Key(Key::Grave, ButtonState::Pressed) => {
+                self.console.toggle();
+            }
+            Event::Key(Key::B, ButtonState::Pressed) => {
                 self.status_text_img = None;
-                self.clear_screen = !self.clear_screen;
+                self.force_brute = !self.force_brute;
+            }
+            Event::Key(Key::I, ButtonState::Pressed) => {
+                self.status_text_img = None;
+                self.integrator = self.integrator.next();
+            }
+            Event::Key(Key::P, ButtonState::Pressed) => {
+                self.status_text_img = None;
+                self.use_softening = !self.use_softening;
+            }
+            Event::Key(Key::M, ButtonState::Pressed) => {
+                self.status_text_img = None;
+                self.merge_collisions = !self.merge_collisions;
+            }
+            Event::MouseButton(MouseButton::Left, ButtonState::Pressed) => {
+                let now = Instant::now();
+                let world_position = self.screen_to_world(window.mouse().pos());
+                let is_double_click = self.last_click_at
+                    .map_or(false, |previous| now.duration_since(previous) < DOUBLE_CLICK_WINDOW);
+                if is_double_click {
+                    if let Some(index) = self.planet_at(world_position) {
+                        self.planets.remove(index);
+                        self.status_text_img = None;
+                    }
+                    self.last_click_at = None;
+                    self.pending_placement = None;
+                    // The first click's release already queued a placement;
+                    // this second press confirms it was a double-click, not
+                    // a real placement, so drop it instead of spawning it.
+                    self.pending_spawn = None;
+                } else {
+                    self.pending_placement = Some((world_position, now));
+                    self.last_click_at = Some(now);
+                }
+            }
+            Event::MouseButton(MouseButton::Left, ButtonState::Released) => {
+                if let Some((press_position, pressed_at)) = self.pending_placement.take() {
+                    let release_position = self.screen_to_world(window.mouse().pos());
+                    let held = pressed_at.elapsed().as_secs_f32();
+                    let planet = Planet {
+                        position: press_position,
+                        velocity: release_position - press_position,
+                        mass: MOUSE_SPAWN_BASE_MASS + held * MOUSE_SPAWN_MASS_PER_SECOND,
+                        color: Color::YELLOW,
+                        trail: Trail::new(press_position),
+                    };
+                    // Don't spawn yet: a second press within DOUBLE_CLICK_WINDOW
+                    // would mean this click was actually the first half of a
+                    // double-click (delete), not a placement. update() commits
+                    // it once that window passes with no second press.
+                    self.pending_spawn = Some((planet, pressed_at));
+                }
+            }
+            Event::Key(Key::Key9, ButtonState::Pressed) => {
+                self.status_text_img = None;
+                self.cvars.theta = (self.cvars.theta - 0.1).max(0.0);
+            }
+            Event::Key(Key::Key0, ButtonState::Pressed) => {
+                self.status_text_img = None;
+                self.cvars.theta += 0.1;
             }
             Event::Key(Key::Space, ButtonState::Pressed) => {
                 self.status_text_img = None;
@@ -155,28 +418,35 @@ impl State for Space {
             }
             Event::Key(Key::Multiply, ButtonState::Pressed) => {
                 self.status_text_img = None;
-                self.time_step *= 2.0;
+                self.cvars.time_step *= 2.0;
             }
             Event::Key(Key::Divide, ButtonState::Pressed) => {
                 self.status_text_img = None;
-                self.time_step /= 2.0;
+                self.cvars.time_step /= 2.0;
             }
             // Add => faster simulation => smaller update rate (update delay)
             Event::Key(Key::Add, ButtonState::Pressed) => {
                 self.status_text_img = None;
-                window.set_update_rate(window.update_rate() / 2.0);
+                self.cvars.update_rate = window.update_rate() / 2.0;
+                window.set_update_rate(self.cvars.update_rate);
             }
             Event::Key(Key::Subtract, ButtonState::Pressed) => {
                 self.status_text_img = None;
-                window.set_update_rate(window.update_rate() * 2.0);
+                self.cvars.update_rate = window.update_rate() * 2.0;
+                window.set_update_rate(self.cvars.update_rate);
             }
             Event::Key(Key::S, ButtonState::Pressed) => {
-                save(APP_NAME, SAVE_PROFILE, &self.planets).expect("Can't save planet data");
+                let data = SaveData { planets: self.planets.clone(), cvars: self.cvars };
+                save(APP_NAME, SAVE_PROFILE, &data).expect("Can't save planet data");
             }
             Event::Key(Key::L, ButtonState::Pressed) => {
-                self.planets = match load::<Vec<Planet>>(APP_NAME, SAVE_PROFILE) {
-                    Ok(planets) => planets,
-                    _ => default_space::get_planets(),
+                match load::<SaveData>(APP_NAME, SAVE_PROFILE) {
+                    Ok(data) => {
+                        self.planets = data.planets;
+                        self.cvars = data.cvars;
+                        window.set_update_rate(self.cvars.update_rate);
+                    }
+                    _ => self.planets = default_space::get_planets(),
                 };
             }
             Event::Key(Key::F1, ButtonState::Pressed) => {
@@ -191,6 +461,12 @@ impl State for Space {
             Event::Key(Key::F4, ButtonState::Pressed) => {
                 self.load_planets("system3.json");
             }
+            Event::Key(Key::F5, ButtonState::Pressed) => {
+                self.run_script("ring.rhai");
+            }
+            Event::Key(Key::F6, ButtonState::Pressed) => {
+                self.run_script("binary.rhai");
+            }
             Event::Key(Key::C, ButtonState::Pressed) => {
                 self.status_text_img = None;
                 self.centered_at = match self.centered_at {
@@ -245,20 +521,34 @@ impl State for Space {
 
         let view_transform = rotate * Transform::translate(-center);
         let view_rectangle = Rectangle::new(-size / 2.0, size); // centered at (0, 0)
+        self.view_transform = view_transform;
 
         // NOTE: view takes transform that is applied at every object in the world.
         // It doesn't transform the view's rectangle
         window.set_view(View::new_transformed(view_rectangle, view_transform));
 
         // background
-        if self.clear_screen { window.clear(Color::BLACK)? };
+        window.clear(Color::BLACK)?;
+
+        // trails, faded from transparent (oldest) to opaque (most recent)
+        for planet in &self.planets {
+            let trail_positions: Vec<Vector> = planet.trail.iter().collect();
+            let segment_count = trail_positions.len();
+            for (i, position) in trail_positions.iter().enumerate() {
+                let alpha = (i + 1) as f32 / (segment_count.max(1) as f32);
+                window.draw(
+                    &Circle::new(*position, planet_radius(planet.mass) * 0.5),
+                    Background::Col(Color { a: alpha, ..planet.color }),
+                );
+            }
+        }
 
         // planets
         for planet in &self.planets {
             window.draw(
                 &Circle::new(
                     planet.position,
-                    if planet.mass > 1.0 { planet.mass.powf(1.0 / 3.0) } else { 1.0 },
+                    planet_radius(planet.mass),
                 ), Background::Col(planet.color),
             );
         }
@@ -277,11 +567,95 @@ impl State for Space {
             None => panic!("Just refreshed status text and there is none"),
         }
 
+        if self.console.open {
+            // Re-rendered every frame (unlike the cached status text above) since
+            // it changes with every keystroke.
+            let text = self.console.render_text();
+            let style = FontStyle::new(16.0, Color::WHITE);
+            let mut img: Option<Image> = None;
+            self.font.execute(|font| {
+                img = Some(font.render(&text, &style)?);
+                Ok(())
+            }).expect("Can't render console text");
+            if let Some(image) = img {
+                window.draw_ex(
+                    &Rectangle::new(-image.area().size / 2.0, image.area().size),
+                    Img(&image),
+                    view_transform.inverse()
+                        * Transform::translate((image.area().size / 2.0) - Vector::new(size.x / 2.0, -size.y / 2.0 + image.area().size.y)),
+                    1,
+                );
+            }
+        }
+
         Ok(())
     }
 }
 
-fn integrate(time_step: f32, planets: &Vec<Planet>) -> Vec<Planet> {
+// Exhaustive O(n^2) reference implementation: sum every other body's pull directly.
+// `eps2` is the squared Plummer softening length (0.0 disables softening).
+fn accelerations_brute_force(bodies: &[(Vector, f32)], eps2: f32) -> Vec<Vector> {
+    bodies.iter().enumerate().map(|(ii, (position, _))| {
+        let mut acceleration: Vector = Vector::new(0, 0);
+        for (jj, (other_position, other_mass)) in bodies.iter().enumerate() {
+            if ii != jj {
+                let distance = *other_position - *position;
+                acceleration += distance * (other_mass / (distance.len2() + eps2).powf(1.5));
+            }
+        }
+        acceleration
+    }).collect()
+}
+
+// Barnes-Hut approximation: O(n log n), trading exactness for speed on large systems.
+fn accelerations_barnes_hut(bodies: &[(Vector, f32)], theta: f32, eps2: f32) -> Vec<Vector> {
+    let tree = QuadTree::build(bodies);
+    bodies.iter().enumerate()
+        .map(|(ii, (position, _))| tree.acceleration_at(ii, *position, theta, eps2))
+        .collect()
+}
+
+fn accelerations(bodies: &[(Vector, f32)], theta: f32, force_brute: bool, eps2: f32) -> Vec<Vector> {
+    if force_brute || bodies.len() < BARNES_HUT_THRESHOLD {
+        accelerations_brute_force(bodies, eps2)
+    } else {
+        accelerations_barnes_hut(bodies, theta, eps2)
+    }
+}
+
+fn accelerations_for(planets: &Vec<Planet>, positions: &[Vector], theta: f32, force_brute: bool, eps2: f32, g: f32) -> Vec<Vector> {
+    let bodies: Vec<(Vector, f32)> = positions.iter().zip(planets.iter())
+        .map(|(position, planet)| (*position, planet.mass))
+        .collect();
+    accelerations(&bodies, theta, force_brute, eps2).iter().map(|a| *a * g).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Integrator {
+    Euler,
+    VelocityVerlet,
+    Rk4,
+}
+
+impl Integrator {
+    fn next(self) -> Integrator {
+        match self {
+            Integrator::Euler => Integrator::VelocityVerlet,
+            Integrator::VelocityVerlet => Integrator::Rk4,
+            Integrator::Rk4 => Integrator::Euler,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Integrator::Euler => "semi-implicit Euler",
+            Integrator::VelocityVerlet => "velocity Verlet",
+            Integrator::Rk4 => "RK4",
+        }
+    }
+}
+
+fn integrate_euler(time_step: f32, planets: &Vec<Planet>, theta: f32, force_brute: bool, eps2: f32, g: f32) -> Vec<Planet> {
     /*
     Integrate with semi-implicit Euler:
         velocity += acceleration * dt;
@@ -289,26 +663,138 @@ fn integrate(time_step: f32, planets: &Vec<Planet>) -> Vec<Planet> {
     i. e. use next-step's velocity when computing position.
     Semi-implicit Euler is first-order (not very precise), symplectic (energy-preserving), fast integrator.
     */
-    let mut new_planets: Vec<Planet> = Vec::new();
-    for (ii, planet) in planets.iter().enumerate() {
-        let mut acceleration: Vector = Vector::new(0, 0);
-        for (jj, other_planet) in planets.iter().enumerate() {
-            if ii != jj {
-                let distance = other_planet.position - planet.position;
-                let acceleration_size = other_planet.mass / distance.len2();
-                acceleration += distance.normalize() * acceleration_size;
-            }
-        }
-        let new_velocity = planet.velocity + acceleration * time_step;
-        let new_planet = Planet {
+    let positions: Vec<Vector> = planets.iter().map(|p| p.position).collect();
+    let accelerations = accelerations_for(planets, &positions, theta, force_brute, eps2, g);
+    planets.iter().zip(accelerations.iter()).map(|(planet, acceleration)| {
+        let new_velocity = planet.velocity + *acceleration * time_step;
+        Planet {
             velocity: new_velocity,
             position: planet.position + new_velocity * time_step,
             mass: planet.mass,
             color: planet.color,
-        };
-        new_planets.push(new_planet);
+            trail: push_trail(planet.trail, planet.position + new_velocity * time_step),
+        }
+    }).collect()
+}
+
+fn integrate_velocity_verlet(time_step: f32, planets: &Vec<Planet>, theta: f32, force_brute: bool, eps2: f32, g: f32) -> Vec<Planet> {
+    /*
+    Velocity Verlet: symplectic and second-order, so it holds up far better than
+    Euler over long runs at the same step size, at the cost of two force
+    evaluations per step instead of one.
+        position += velocity*dt + 0.5*a(t)*dt^2
+        velocity += 0.5*(a(t) + a(t+dt))*dt
+    */
+    let old_positions: Vec<Vector> = planets.iter().map(|p| p.position).collect();
+    let old_accelerations = accelerations_for(planets, &old_positions, theta, force_brute, eps2, g);
+
+    let new_positions: Vec<Vector> = planets.iter().zip(old_accelerations.iter())
+        .map(|(planet, a)| planet.position + planet.velocity * time_step + *a * (0.5 * time_step * time_step))
+        .collect();
+    let new_accelerations = accelerations_for(planets, &new_positions, theta, force_brute, eps2, g);
+
+    planets.iter().enumerate().map(|(ii, planet)| {
+        let new_velocity = planet.velocity + (old_accelerations[ii] + new_accelerations[ii]) * (0.5 * time_step);
+        Planet {
+            velocity: new_velocity,
+            position: new_positions[ii],
+            mass: planet.mass,
+            color: planet.color,
+            trail: push_trail(planet.trail, new_positions[ii]),
+        }
+    }).collect()
+}
+
+fn integrate_rk4(time_step: f32, planets: &Vec<Planet>, theta: f32, force_brute: bool, eps2: f32, g: f32) -> Vec<Planet> {
+    /*
+    Classic fourth-order Runge-Kutta on the (position, velocity) state, weighted
+    1/6, 1/3, 1/3, 1/6. Not symplectic, but very accurate for a given step size.
+    */
+    let p0: Vec<Vector> = planets.iter().map(|p| p.position).collect();
+    let v0: Vec<Vector> = planets.iter().map(|p| p.velocity).collect();
+
+    let a1 = accelerations_for(planets, &p0, theta, force_brute, eps2, g);
+
+    let p2: Vec<Vector> = (0..planets.len()).map(|i| p0[i] + v0[i] * (0.5 * time_step)).collect();
+    let v2: Vec<Vector> = (0..planets.len()).map(|i| v0[i] + a1[i] * (0.5 * time_step)).collect();
+    let a2 = accelerations_for(planets, &p2, theta, force_brute, eps2, g);
+
+    let p3: Vec<Vector> = (0..planets.len()).map(|i| p0[i] + v2[i] * (0.5 * time_step)).collect();
+    let v3: Vec<Vector> = (0..planets.len()).map(|i| v0[i] + a2[i] * (0.5 * time_step)).collect();
+    let a3 = accelerations_for(planets, &p3, theta, force_brute, eps2, g);
+
+    let p4: Vec<Vector> = (0..planets.len()).map(|i| p0[i] + v3[i] * time_step).collect();
+    let v4: Vec<Vector> = (0..planets.len()).map(|i| v0[i] + a3[i] * time_step).collect();
+    let a4 = accelerations_for(planets, &p4, theta, force_brute, eps2, g);
+
+    planets.iter().enumerate().map(|(ii, planet)| {
+        let new_velocity = v0[ii] + (a1[ii] + a2[ii] * 2.0 + a3[ii] * 2.0 + a4[ii]) * (time_step / 6.0);
+        let new_position = p0[ii] + (v0[ii] + v2[ii] * 2.0 + v3[ii] * 2.0 + v4[ii]) * (time_step / 6.0);
+        Planet {
+            velocity: new_velocity,
+            position: new_position,
+            mass: planet.mass,
+            color: planet.color,
+            trail: push_trail(planet.trail, new_position),
+        }
+    }).collect()
+}
+
+fn integrate(time_step: f32, planets: &Vec<Planet>, theta: f32, force_brute: bool, eps2: f32, g: f32, integrator: Integrator) -> Vec<Planet> {
+    match integrator {
+        Integrator::Euler => integrate_euler(time_step, planets, theta, force_brute, eps2, g),
+        Integrator::VelocityVerlet => integrate_velocity_verlet(time_step, planets, theta, force_brute, eps2, g),
+        Integrator::Rk4 => integrate_rk4(time_step, planets, theta, force_brute, eps2, g),
+    }
+}
+
+// Same radius `draw` uses, pulled out so collision detection agrees with what's on screen.
+fn planet_radius(mass: f32) -> f32 {
+    if mass > 1.0 { mass.powf(1.0 / 3.0) } else { 1.0 }
+}
+
+fn push_trail(mut trail: Trail, position: Vector) -> Trail {
+    trail.push(position);
+    trail
+}
+
+fn merge_planets(a: Planet, b: Planet) -> Planet {
+    let total_mass = a.mass + b.mass;
+    let weight_a = a.mass / total_mass;
+    let position = a.position * weight_a + b.position * (1.0 - weight_a);
+    Planet {
+        position,
+        velocity: (a.velocity * a.mass + b.velocity * b.mass) * (1.0 / total_mass),
+        mass: total_mass,
+        color: Color {
+            r: a.color.r * weight_a + b.color.r * (1.0 - weight_a),
+            g: a.color.g * weight_a + b.color.g * (1.0 - weight_a),
+            b: a.color.b * weight_a + b.color.b * (1.0 - weight_a),
+            a: a.color.a * weight_a + b.color.a * (1.0 - weight_a),
+        },
+        trail: Trail::new(position),
+    }
+}
+
+// Replace any planets that overlap (separation below the sum of their drawn
+// radii) with a single merged body that conserves mass and momentum.
+fn merge_collisions(planets: &Vec<Planet>) -> Vec<Planet> {
+    let mut already_merged = vec![false; planets.len()];
+    let mut result: Vec<Planet> = Vec::new();
+    for ii in 0..planets.len() {
+        if already_merged[ii] { continue; }
+        let mut merged = planets[ii];
+        for jj in (ii + 1)..planets.len() {
+            if already_merged[jj] { continue; }
+            let separation = (planets[jj].position - merged.position).len();
+            if separation < planet_radius(merged.mass) + planet_radius(planets[jj].mass) {
+                merged = merge_planets(merged, planets[jj]);
+                already_merged[jj] = true;
+            }
+        }
+        result.push(merged);
     }
-    new_planets
+    result
 }
 
 fn main() {
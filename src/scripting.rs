@@ -0,0 +1,39 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use quicksilver::geom::Vector;
+use quicksilver::graphics::Color;
+use quicksilver::load_file;
+use quicksilver::prelude::Future;
+use rhai::{Engine, Scope};
+
+use crate::trail::Trail;
+use crate::Planet;
+
+// Scripts build a system by calling `planet(position, velocity, mass, color)`
+// for each body they want; this collects whatever they call it with. Loaded
+// through the same load_file asset path load_planets uses, rather than
+// std::fs, so scripts are found wherever the bundled JSON systems are.
+pub fn run(path: &str) -> std::result::Result<Vec<Planet>, String> {
+    let bytes = load_file(path).wait().map_err(|err| format!("can't read {}: {}", path, err))?;
+    let source = String::from_utf8(bytes).map_err(|err| format!("can't read {}: {}", path, err))?;
+
+    let planets: Rc<RefCell<Vec<Planet>>> = Rc::new(RefCell::new(Vec::new()));
+
+    let mut engine = Engine::new();
+    engine.register_type::<Vector>();
+    engine.register_type::<Color>();
+    engine.register_fn("vector", |x: f64, y: f64| Vector::new(x as f32, y as f32));
+    engine.register_fn("color", |r: f64, g: f64, b: f64| Color { r: r as f32, g: g as f32, b: b as f32, a: 1.0 });
+
+    let sink = planets.clone();
+    engine.register_fn("planet", move |position: Vector, velocity: Vector, mass: f64, color: Color| {
+        sink.borrow_mut().push(Planet { position, velocity, mass: mass as f32, color, trail: Trail::new(position) });
+    });
+
+    let mut scope = Scope::new();
+    engine.eval_with_scope::<()>(&mut scope, &source).map_err(|err| format!("error in {}: {}", path, err))?;
+
+    drop(engine);
+    Ok(Rc::try_unwrap(planets).expect("script still holds a reference to its planet sink").into_inner())
+}
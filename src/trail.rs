@@ -0,0 +1,34 @@
+use quicksilver::geom::Vector;
+use serde_derive::*;
+
+/// How many past positions each planet remembers for its motion trail.
+pub const TRAIL_LENGTH: usize = 24;
+
+/// Fixed-capacity ring buffer of a planet's recent positions, oldest to newest.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Trail {
+    positions: [Vector; TRAIL_LENGTH],
+    head: usize,
+    len: usize,
+}
+
+impl Trail {
+    pub fn new(initial_position: Vector) -> Trail {
+        Trail { positions: [initial_position; TRAIL_LENGTH], head: 0, len: 0 }
+    }
+
+    pub fn push(&mut self, position: Vector) {
+        self.positions[self.head] = position;
+        self.head = (self.head + 1) % TRAIL_LENGTH;
+        self.len = (self.len + 1).min(TRAIL_LENGTH);
+    }
+
+    /// Iterates stored positions oldest-first, so the caller can fade alpha
+    /// from transparent at the tail to opaque at the head.
+    pub fn iter(&self) -> impl Iterator<Item=Vector> + '_ {
+        (0..self.len).map(move |i| {
+            let index = (self.head + TRAIL_LENGTH - self.len + i) % TRAIL_LENGTH;
+            self.positions[index]
+        })
+    }
+}